@@ -1,4 +1,7 @@
 use crate::parser;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::collections::HashSet;
 use std::fmt;
 
 // Types
@@ -15,8 +18,13 @@ pub enum Term {
   Lam { name: String, body: BTerm },
   App { func: BTerm, argm: BTerm },
   Ctr { name: String, args: Vec<BTerm> },
+  Fun { name: String, args: Vec<BTerm> },
   Num { numb: u64 },
   Op2 { oper: Oper, val0: BTerm, val1: BTerm },
+  // Case analysis over `expr`, one `(pattern, body)` per arm. Only produced by `parse_match`
+  // and consumed by `desugar_matches`, which lowers it into a fresh top-level `Entry` before
+  // anything else (the runtime and `adjust` never need to see it).
+  Match { expr: BTerm, arms: Vec<(BTerm, BTerm)> },
 }
 
 pub type BTerm = Box<Term>;
@@ -50,11 +58,41 @@ pub struct Rule {
   pub rhs: BTerm,
 }
 
+// Argument
+// --------
+
+#[derive(Clone, Debug)]
+pub struct Argument {
+  pub eras: bool,
+  pub name: String,
+  pub tipo: BTerm,
+}
+
+// Entry
+// -----
+
+// A named, typed, multi-clause function: `Add (a: Nat) (b: Nat) : Nat` followed by the
+// equations for `Add`. Desugars down to `rules` for the runtime.
+#[derive(Clone, Debug)]
+pub struct Entry {
+  pub name: String,
+  pub args: Vec<Argument>,
+  pub tipo: BTerm,
+  pub rules: Vec<Rule>,
+}
+
 // File
 // ----
 
 pub struct File {
-  pub rules: Vec<Rule>,
+  pub entries: Vec<Entry>,
+}
+
+impl File {
+  // Flattens the grouped entries back down to the flat rule list the runtime expects.
+  pub fn rules(&self) -> impl Iterator<Item = &Rule> {
+    self.entries.iter().flat_map(|entry| entry.rules.iter())
+  }
 }
 
 // Stringifier
@@ -91,86 +129,189 @@ impl fmt::Display for Oper {
 }
 
 impl fmt::Display for Term {
-  // WARN: I think this could overflow, might need to rewrite it to be iterative instead of recursive?
-  // NOTE: Another issue is complexity. This function is O(N^2). Should use ropes to be linear.
+  // Walks the term with an explicit work stack instead of the call stack, so a long chain of
+  // lambdas, applications or constructors, or a 10-million-node string literal, prints
+  // without overflowing, and appends fragments into a single reused buffer. List sugar
+  // renders each element with a nested `Display` call, so list literals nested inside list
+  // literals use one call-stack frame per level of nesting. A `Cons`/`StrCons` chain that
+  // doesn't end in `Nil`/`StrNil` is re-probed from every node of its spine, which costs
+  // time quadratic in its length.
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    // A unit of pending work: either a literal fragment to append, or a subterm left to visit.
+    enum Item<'a> {
+      Emit(Cow<'a, str>),
+      Visit(&'a Term),
+    }
+
+    // Walks a `Cons`/`Nil` spine iteratively, so an arbitrarily long list never recurses.
+    // Each element is still rendered through `Display`, but that's a fresh top-level call,
+    // not additional depth tied to the list's length.
     fn lst_sugar(term: &Term) -> Option<String> {
-      fn go(term: &Term, text: &mut String, fst: bool) -> Option<()> {
-        if let Term::Ctr { name, args } = term {
-          if name == "Cons" && args.len() == 2 {
+      let mut text = String::from("[");
+      let mut node = term;
+      let mut fst = true;
+      loop {
+        match node {
+          Term::Ctr { name, args } if name == "Cons" && args.len() == 2 => {
             if !fst {
               text.push_str(", ");
             }
             text.push_str(&format!("{}", args[0]));
-            go(&args[1], text, false)?;
-            return Some(());
-          }
-          if name == "Nil" && args.is_empty() {
-            return Some(());
+            fst = false;
+            node = &args[1];
           }
+          Term::Ctr { name, args } if name == "Nil" && args.is_empty() => break,
+          _ => return None,
         }
-        None
       }
-      let mut result = String::new();
-      result.push('[');
-      go(term, &mut result, true)?;
-      result.push(']');
-      Some(result)
+      text.push(']');
+      Some(text)
     }
 
+    // Re-escapes a character read out of a `StrCons` spine, so printing a parsed string
+    // yields valid source that reparses to the identical term.
+    fn escape_str_char(chr: char) -> Cow<'static, str> {
+      match chr {
+        '\n' => Cow::Borrowed("\\n"),
+        '\t' => Cow::Borrowed("\\t"),
+        '\r' => Cow::Borrowed("\\r"),
+        '\\' => Cow::Borrowed("\\\\"),
+        '"' => Cow::Borrowed("\\\""),
+        '\0' => Cow::Borrowed("\\0"),
+        chr if (chr as u32) < 0x20 || (chr as u32) == 0x7f => {
+          Cow::Owned(format!("\\u{{{:x}}}", chr as u32))
+        }
+        chr => Cow::Owned(chr.to_string()),
+      }
+    }
+
+    // Walks a `StrCons`/`StrNil` spine iteratively, for the same reason as `lst_sugar`.
     fn str_sugar(term: &Term) -> Option<String> {
-      fn go(term: &Term, text: &mut String) -> Option<()> {
-        if let Term::Ctr { name, args } = term {
-          if name == "StrCons" && args.len() == 2 {
+      let mut text = String::from("\"");
+      let mut node = term;
+      loop {
+        match node {
+          Term::Ctr { name, args } if name == "StrCons" && args.len() == 2 => {
             if let Term::Num { numb } = *args[0] {
-              text.push(std::char::from_u32(numb as u32)?);
-              go(&args[1], text)?;
+              let chr = std::char::from_u32(numb as u32)?;
+              text.push_str(&escape_str_char(chr));
+              node = &args[1];
+            } else {
+              return None;
             }
-            return Some(());
-          }
-          if name == "StrNil" && args.is_empty() {
-            return Some(());
           }
+          Term::Ctr { name, args } if name == "StrNil" && args.is_empty() => break,
+          _ => return None,
         }
-        None
       }
-      let mut result = String::new();
-      result.push('"');
-      go(term, &mut result)?;
-      result.push('"');
-      Some(result)
-    }
-    match self {
-      Self::Var { name } => write!(f, "{}", name),
-      Self::Dup { nam0, nam1, expr, body } => {
-        write!(f, "dup {} {} = {}; {}", nam0, nam1, expr, body)
-      }
-      Self::Let { name, expr, body } => write!(f, "let {} = {}; {}", name, expr, body),
-      Self::Lam { name, body } => write!(f, "λ{} {}", name, body),
-      Self::App { func, argm } => {
-        let mut args = vec![argm];
-        let mut expr = func;
-        while let Self::App { func, argm } = &**expr {
-          args.push(argm);
-          expr = func;
-        }
-        args.reverse();
-        write!(f, "({} {})", expr, args.iter().map(|x| format!("{}",x)).collect::<Vec<String>>().join(" "))
-      },
-      Self::Ctr { name, args } => {
-        // Ctr sugars
-        let sugars = [str_sugar, lst_sugar];
-        for sugar in sugars {
-          if let Some(term) = sugar(self) {
-            return write!(f, "{}", term);
+      text.push('"');
+      Some(text)
+    }
+
+    // Pushes `seq` onto `stack` so it pops back off in the same order it was written.
+    fn push_seq<'a>(stack: &mut Vec<Item<'a>>, seq: Vec<Item<'a>>) {
+      stack.extend(seq.into_iter().rev());
+    }
+
+    let mut out = String::new();
+    let mut stack = vec![Item::Visit(self)];
+    while let Some(item) = stack.pop() {
+      match item {
+        Item::Emit(text) => out.push_str(&text),
+        Item::Visit(term) => match term {
+          Term::Var { name } => out.push_str(name),
+          Term::Num { numb } => {
+            out.push_str(&numb.to_string());
           }
-        }
+          Term::Dup { nam0, nam1, expr, body } => push_seq(
+            &mut stack,
+            vec![
+              Item::Emit(Cow::Owned(format!("dup {} {} = ", nam0, nam1))),
+              Item::Visit(expr),
+              Item::Emit(Cow::Borrowed("; ")),
+              Item::Visit(body),
+            ],
+          ),
+          Term::Let { name, expr, body } => push_seq(
+            &mut stack,
+            vec![
+              Item::Emit(Cow::Owned(format!("let {} = ", name))),
+              Item::Visit(expr),
+              Item::Emit(Cow::Borrowed("; ")),
+              Item::Visit(body),
+            ],
+          ),
+          Term::Lam { name, body } => push_seq(
+            &mut stack,
+            vec![Item::Emit(Cow::Owned(format!("λ{} ", name))), Item::Visit(body)],
+          ),
+          Term::App { func, argm } => {
+            let mut args = vec![argm.as_ref()];
+            let mut expr = func.as_ref();
+            while let Term::App { func, argm } = expr {
+              args.push(argm.as_ref());
+              expr = func.as_ref();
+            }
+            args.reverse();
+            let mut seq = vec![Item::Emit(Cow::Borrowed("(")), Item::Visit(expr)];
+            for arg in args {
+              seq.push(Item::Emit(Cow::Borrowed(" ")));
+              seq.push(Item::Visit(arg));
+            }
+            seq.push(Item::Emit(Cow::Borrowed(")")));
+            push_seq(&mut stack, seq);
+          }
+          Term::Ctr { name, args } => {
+            // Ctr sugars
+            let sugars = [str_sugar, lst_sugar];
+            if let Some(text) = sugars.iter().find_map(|sugar| sugar(term)) {
+              stack.push(Item::Emit(Cow::Owned(text)));
+              continue;
+            }
 
-        write!(f, "({}{})", name, args.iter().map(|x| format!(" {}", x)).collect::<String>())
+            let mut seq = vec![Item::Emit(Cow::Owned(format!("({}", name)))];
+            for arg in args {
+              seq.push(Item::Emit(Cow::Borrowed(" ")));
+              seq.push(Item::Visit(arg));
+            }
+            seq.push(Item::Emit(Cow::Borrowed(")")));
+            push_seq(&mut stack, seq);
+          }
+          Term::Fun { name, args } => {
+            let mut seq = vec![Item::Emit(Cow::Owned(format!("({}", name)))];
+            for arg in args {
+              seq.push(Item::Emit(Cow::Borrowed(" ")));
+              seq.push(Item::Visit(arg));
+            }
+            seq.push(Item::Emit(Cow::Borrowed(")")));
+            push_seq(&mut stack, seq);
+          }
+          Term::Op2 { oper, val0, val1 } => push_seq(
+            &mut stack,
+            vec![
+              Item::Emit(Cow::Owned(format!("({} ", oper))),
+              Item::Visit(val0),
+              Item::Emit(Cow::Borrowed(" ")),
+              Item::Visit(val1),
+              Item::Emit(Cow::Borrowed(")")),
+            ],
+          ),
+          Term::Match { expr, arms } => {
+            let mut seq =
+              vec![Item::Emit(Cow::Borrowed("match ")), Item::Visit(expr), Item::Emit(Cow::Borrowed(" { "))];
+            for (patt, body) in arms {
+              seq.push(Item::Visit(patt));
+              seq.push(Item::Emit(Cow::Borrowed(": ")));
+              seq.push(Item::Visit(body));
+              seq.push(Item::Emit(Cow::Borrowed("; ")));
+            }
+            seq.push(Item::Emit(Cow::Borrowed("}")));
+            push_seq(&mut stack, seq);
+          }
+        },
       }
-      Self::Num { numb } => write!(f, "{}", numb),
-      Self::Op2 { oper, val0, val1 } => write!(f, "({} {} {})", oper, val0, val1),
     }
+    write!(f, "{}", out)
   }
 }
 
@@ -183,6 +324,35 @@ impl fmt::Display for Rule {
   }
 }
 
+// Argument
+// --------
+
+impl fmt::Display for Argument {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "({}{}: {})", if self.eras { "-" } else { "" }, self.name, self.tipo)
+  }
+}
+
+// Entry
+// -----
+
+impl fmt::Display for Entry {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    writeln!(
+      f,
+      "{}{} : {}",
+      self.name,
+      self.args.iter().map(|arg| format!(" {}", arg)).collect::<String>(),
+      self.tipo
+    )?;
+    write!(
+      f,
+      "{}",
+      self.rules.iter().map(|rule| format!("{}", rule)).collect::<Vec<String>>().join("\n")
+    )
+  }
+}
+
 // File
 // ----
 
@@ -191,7 +361,7 @@ impl fmt::Display for File {
     write!(
       f,
       "{}",
-      self.rules.iter().map(|rule| format!("{}", rule)).collect::<Vec<String>>().join("\n")
+      self.entries.iter().map(|entry| format!("{}", entry)).collect::<Vec<String>>().join("\n\n")
     )
   }
 }
@@ -232,6 +402,50 @@ pub fn parse_dup(state: parser::State) -> parser::Answer<Option<BTerm>> {
   );
 }
 
+// Reads the statements inside a `do` block and desugars them into bind/pure applications:
+// `ask x = e; rest` becomes `(NAME.bind e λx rest)`, `return e` becomes `(NAME.pure e)`, and
+// a trailing bare expression stands for itself. Chains left-to-right via plain recursion, so
+// `ask` after `ask` nests one bind inside the previous one's continuation.
+fn parse_do_stmts<'a>(monad: &str, state: parser::State<'a>) -> parser::Answer<'a, BTerm> {
+  let (state, is_ask) = parser::text("ask ", state)?;
+  if is_ask {
+    let (state, name) = parser::name1(state)?;
+    let (state, _) = parser::consume("=", state)?;
+    let (state, expr) = parse_term(state)?;
+    let (state, _) = parser::text(";", state)?;
+    let (state, rest) = parse_do_stmts(monad, state)?;
+    let bind = Box::new(Term::Var { name: format!("{}.bind", monad) });
+    let cont = Box::new(Term::Lam { name, body: rest });
+    return Ok((
+      state,
+      Box::new(Term::App { func: Box::new(Term::App { func: bind, argm: expr }), argm: cont }),
+    ));
+  }
+  let (state, is_return) = parser::text("return ", state)?;
+  if is_return {
+    let (state, expr) = parse_term(state)?;
+    let (state, _) = parser::maybe(parser::text_parser(";"), state)?;
+    let pure = Box::new(Term::Var { name: format!("{}.pure", monad) });
+    return Ok((state, Box::new(Term::App { func: pure, argm: expr })));
+  }
+  parse_term(state)
+}
+
+pub fn parse_do(state: parser::State) -> parser::Answer<Option<BTerm>> {
+  parser::guard(
+    parser::text_parser("do "),
+    Box::new(|state| {
+      let (state, _) = parser::consume("do ", state)?;
+      let (state, name) = parser::name1(state)?;
+      let (state, _) = parser::consume("{", state)?;
+      let (state, body) = parse_do_stmts(&name, state)?;
+      let (state, _) = parser::consume("}", state)?;
+      Ok((state, body))
+    }),
+    state,
+  )
+}
+
 pub fn parse_lam(state: parser::State) -> parser::Answer<Option<BTerm>> {
   let parse_symbol =
     |x| parser::parser_or(&[parser::text_parser("λ"), parser::text_parser("@")], x);
@@ -247,6 +461,51 @@ pub fn parse_lam(state: parser::State) -> parser::Answer<Option<BTerm>> {
   )
 }
 
+// Looks ahead for `name => ...` / `name0 name1 ... =>`, without consuming on failure, so
+// `parse_arrow_lam` only commits once it's sure an arrow follows.
+fn peek_arrow_lam(state: parser::State) -> parser::Answer<bool> {
+  let mut state = state;
+  loop {
+    let (new_state, name) = parser::name(state)?;
+    if name.is_empty() {
+      return Ok((state, false));
+    }
+    state = new_state;
+    let (new_state, arrow) = parser::text("=>", state)?;
+    if arrow {
+      return Ok((new_state, true));
+    }
+    state = new_state;
+  }
+}
+
+// `x => body`, and `x y z => body` desugared into nested `Lam`s, one per argument, binding
+// left to right. An alternative spelling of `λx body` that omits the `λ`/`@` sigil.
+pub fn parse_arrow_lam(state: parser::State) -> parser::Answer<Option<BTerm>> {
+  parser::guard(
+    Box::new(peek_arrow_lam),
+    Box::new(|state| {
+      let mut names = Vec::new();
+      let mut state = state;
+      loop {
+        let (new_state, name) = parser::name(state)?;
+        state = new_state;
+        names.push(name);
+        let (new_state, arrow) = parser::text("=>", state)?;
+        state = new_state;
+        if arrow {
+          break;
+        }
+      }
+      let (state, body) = parse_term(state)?;
+      let lam =
+        names.into_iter().rev().fold(body, |body, name| Box::new(Term::Lam { name, body }));
+      Ok((state, lam))
+    }),
+    state,
+  )
+}
+
 pub fn parse_app(state: parser::State) -> parser::Answer<Option<BTerm>> {
   return parser::guard(
     parser::text_parser("("),
@@ -375,6 +634,66 @@ pub fn parse_var(state: parser::State) -> parser::Answer<Option<BTerm>> {
   )
 }
 
+// Reads `n` hex digits starting right after a `\x` marker.
+fn parse_hex_digits(state: parser::State, n: usize) -> parser::Answer<u32> {
+  let mut state = state;
+  let mut value: u32 = 0;
+  for _ in 0..n {
+    match parser::head(state) {
+      Some(c) if c.is_ascii_hexdigit() => {
+        value = value * 16 + c.to_digit(16).unwrap();
+        state = parser::tail(state);
+      }
+      _ => return parser::expected("hex digit", 1, state),
+    }
+  }
+  Ok((state, value))
+}
+
+// `\u{...}`: a brace-delimited, variable-width hex escape.
+fn parse_unicode_escape(state: parser::State) -> parser::Answer<char> {
+  let (state, _) = parser::consume("{", state)?;
+  let mut state = state;
+  let mut value: u32 = 0;
+  let mut digits = 0;
+  loop {
+    match parser::head(state) {
+      Some('}') if digits == 0 => return parser::expected("hex digit", 1, state),
+      Some('}') => break,
+      Some(c) if c.is_ascii_hexdigit() => {
+        value = value * 16 + c.to_digit(16).unwrap();
+        digits += 1;
+        state = parser::tail(state);
+      }
+      _ => return parser::expected("hex digit or '}'", 1, state),
+    }
+  }
+  let (state, _) = parser::consume("}", state)?;
+  match std::char::from_u32(value) {
+    Some(chr) => Ok((state, chr)),
+    None => parser::expected("valid unicode scalar value", 1, state),
+  }
+}
+
+// Reads the character right after a `\`, shared by `parse_chr_sugar` and `parse_str_sugar`.
+fn parse_escape(state: parser::State) -> parser::Answer<char> {
+  match parser::head(state) {
+    Some('n') => Ok((parser::tail(state), '\n')),
+    Some('t') => Ok((parser::tail(state), '\t')),
+    Some('r') => Ok((parser::tail(state), '\r')),
+    Some('\\') => Ok((parser::tail(state), '\\')),
+    Some('"') => Ok((parser::tail(state), '"')),
+    Some('\'') => Ok((parser::tail(state), '\'')),
+    Some('0') => Ok((parser::tail(state), '\0')),
+    Some('x') => {
+      let (state, value) = parse_hex_digits(parser::tail(state), 2)?;
+      Ok((state, std::char::from_u32(value).unwrap_or('\u{fffd}')))
+    }
+    Some('u') => parse_unicode_escape(parser::tail(state)),
+    _ => parser::expected("escape sequence", 1, state),
+  }
+}
+
 pub fn parse_chr_sugar(state: parser::State) -> parser::Answer<Option<BTerm>> {
   parser::guard(
     Box::new(|state| {
@@ -383,19 +702,18 @@ pub fn parse_chr_sugar(state: parser::State) -> parser::Answer<Option<BTerm>> {
     }),
     Box::new(|state| {
       let (state, _) = parser::text("'", state)?;
-      if let Some(c) = parser::head(state) {
-        let state = parser::tail(state);
-        let (state, _) = parser::text("'", state)?;
-        Ok((state, Box::new(Term::Num { numb: c as u64 })))
-      } else {
-        parser::expected("character", 1, state)
-      }
+      let (state, chr) = match parser::head(state) {
+        Some('\\') => parse_escape(parser::tail(state))?,
+        Some(c) => (parser::tail(state), c),
+        None => return parser::expected("character", 1, state),
+      };
+      let (state, _) = parser::consume("'", state)?;
+      Ok((state, Box::new(Term::Num { numb: chr as u64 })))
     }),
     state,
   )
 }
 
-// TODO: parse escape sequences
 pub fn parse_str_sugar(state: parser::State) -> parser::Answer<Option<BTerm>> {
   parser::guard(
     Box::new(|state| {
@@ -404,15 +722,21 @@ pub fn parse_str_sugar(state: parser::State) -> parser::Answer<Option<BTerm>> {
     }),
     Box::new(|state| {
       let delim = parser::head(state).unwrap_or('\0');
-      let state = parser::tail(state);
+      let mut state = parser::tail(state);
       let mut chars: Vec<char> = Vec::new();
-      let mut state = state;
       loop {
-        if let Some(next) = parser::head(state) {
-          if next == delim || next == '\0' {
+        match parser::head(state) {
+          None => return parser::expected("closing quote", 1, state),
+          Some(next) if next == delim => {
             state = parser::tail(state);
             break;
-          } else {
+          }
+          Some('\\') => {
+            let (new_state, chr) = parse_escape(parser::tail(state))?;
+            chars.push(chr);
+            state = new_state;
+          }
+          Some(next) => {
             chars.push(next);
             state = parser::tail(state);
           }
@@ -459,13 +783,43 @@ pub fn parse_lst_sugar(state: parser::State) -> parser::Answer<Option<BTerm>> {
   )
 }
 
+// `match x { (Cons h t): <body>; Nil: <body> }`: case analysis over a scrutinee, reading a
+// brace-delimited list of `<pattern>: <term>` arms, `;`-separated. Produces a transient
+// `Term::Match` that `desugar_matches` later lowers into a fresh top-level entry.
+pub fn parse_match(state: parser::State) -> parser::Answer<Option<BTerm>> {
+  parser::guard(
+    parser::text_parser("match "),
+    Box::new(|state| {
+      let (state, _) = parser::consume("match ", state)?;
+      let (state, expr) = parse_term(state)?;
+      let (state, _) = parser::consume("{", state)?;
+      let (state, arms) = parser::until(
+        parser::text_parser("}"),
+        Box::new(|state| {
+          let (state, patt) = parse_term(state)?;
+          let (state, _) = parser::consume(":", state)?;
+          let (state, body) = parse_term(state)?;
+          let (state, _) = parser::maybe(parser::text_parser(";"), state)?;
+          Ok((state, (patt, body)))
+        }),
+        state,
+      )?;
+      Ok((state, Box::new(Term::Match { expr, arms })))
+    }),
+    state,
+  )
+}
+
 pub fn parse_term(state: parser::State) -> parser::Answer<BTerm> {
   parser::grammar(
     "Term",
     &[
       Box::new(parse_let),
       Box::new(parse_dup),
+      Box::new(parse_do),
+      Box::new(parse_match),
       Box::new(parse_lam),
+      Box::new(parse_arrow_lam),
       Box::new(parse_ctr),
       Box::new(parse_op2),
       Box::new(parse_app),
@@ -480,37 +834,167 @@ pub fn parse_term(state: parser::State) -> parser::Answer<BTerm> {
   )
 }
 
+// A term can start with any of these, so a rule's right-hand side can tell whether another
+// application argument follows without consuming it (see `parse_rule_rhs`). Note a bare
+// uppercase letter is deliberately excluded: it may be the start of the *next* entry's
+// signature line, so a nullary constructor or function passed as a further argument has to
+// be written parenthesized (`(Name)`).
+fn peek_term_start(state: parser::State) -> parser::Answer<bool> {
+  let (state, head) = parser::get_char(state)?;
+  Ok((state, matches!(head, 'a'..='z' | '0'..='9' | '_' | '$' | '(' | '[' | '"' | '`' | '\'' | 'λ' | '@')))
+}
+
+// An entry's equations write their head as a plain, parenthesized constructor application
+// (e.g. `(Add a (S b))`), since the parser doesn't distinguish function calls from
+// constructors (that's left to the `adjust` pass). This reads that head back out.
+fn term_head_name(term: &Term) -> Option<&str> {
+  match term {
+    Term::Ctr { name, .. } => Some(name),
+    _ => None,
+  }
+}
+
+fn rule_head_name(rule: &Rule) -> Option<&str> {
+  term_head_name(&rule.lhs)
+}
+
+// Peeks whether `state` sits right at the start of another `(own_name ...)` equation, without
+// consuming. A bare-application RHS must stop there instead of swallowing a sibling equation
+// as a spurious trailing argument — the two are otherwise indistinguishable, since both an
+// argument and the next equation's head can start with `(`.
+fn peek_next_equation<'a>(own_name: &str, state: parser::State<'a>) -> parser::Answer<'a, bool> {
+  let (state, open) = parser::text("(", state)?;
+  if !open {
+    return Ok((state, false));
+  }
+  let (state, name) = parser::name(state)?;
+  Ok((state, !name.is_empty() && name == own_name))
+}
+
+// A rule's right-hand side may be a bare application sequence, not necessarily wrapped in
+// parens: `Add a (S b)` is read the same as `(Add a (S b))`. A bare constructor/function
+// head takes each further term as an argument; any other head has them folded into `App` on
+// the left. Stops as soon as what follows looks like the next `own_name` equation,
+// so it never swallows a sibling rule as a spurious trailing argument.
+fn parse_rule_rhs<'a>(own_name: &str, state: parser::State<'a>) -> parser::Answer<'a, BTerm> {
+  let (_, head) = parser::get_char(state)?;
+  let bare_head = head.is_ascii_uppercase();
+  let (state, mut expr) = parse_term(state)?;
+  let mut state = state;
+  loop {
+    let (_, boundary) = peek_next_equation(own_name, state)?;
+    if boundary {
+      return Ok((state, expr));
+    }
+    let (new_state, more) = peek_term_start(state)?;
+    if !more {
+      return Ok((state, expr));
+    }
+    let (new_state, argm) = parse_term(new_state)?;
+    expr = match *expr {
+      Term::Ctr { name, mut args } if bare_head => {
+        args.push(argm);
+        Box::new(Term::Ctr { name, args })
+      }
+      func => Box::new(Term::App { func: Box::new(func), argm }),
+    };
+    state = new_state;
+  }
+}
+
 pub fn parse_rule(state: parser::State) -> parser::Answer<Option<Rule>> {
   return parser::guard(
-    parser::text_parser(""),
+    parser::text_parser("("),
     Box::new(|state| {
       let (state, lhs) = parse_term(state)?;
       let (state, _) = parser::consume("=", state)?;
-      let (state, rhs) = parse_term(state)?;
+      let own_name = term_head_name(&lhs).unwrap_or("").to_string();
+      let (state, rhs) = parse_rule_rhs(&own_name, state)?;
       Ok((state, Rule { lhs, rhs }))
     }),
     state,
   );
 }
 
+pub fn parse_argument(state: parser::State) -> parser::Answer<Option<Argument>> {
+  parser::guard(
+    parser::text_parser("("),
+    Box::new(|state| {
+      let (state, _) = parser::consume("(", state)?;
+      let (state, eras) = parser::text("-", state)?;
+      let (state, name) = parser::name1(state)?;
+      let (state, _) = parser::consume(":", state)?;
+      let (state, tipo) = parse_term(state)?;
+      let (state, _) = parser::consume(")", state)?;
+      Ok((state, Argument { eras, name, tipo }))
+    }),
+    state,
+  )
+}
+
+pub fn parse_arguments(state: parser::State) -> parser::Answer<Vec<Argument>> {
+  let mut args = Vec::new();
+  let mut state = state;
+  loop {
+    let (new_state, arg) = parse_argument(state)?;
+    state = new_state;
+    match arg {
+      Some(arg) => args.push(arg),
+      None => break,
+    }
+  }
+  Ok((state, args))
+}
+
+// Reads a signature line (`Add (a: Nat) (b: Nat) : Nat`) followed by the contiguous
+// equations whose head matches `name`, grouping them into one `Entry`.
+pub fn parse_entry(state: parser::State) -> parser::Answer<Option<Entry>> {
+  parser::guard(
+    Box::new(|state| {
+      let (state, head) = parser::get_char(state)?;
+      Ok((state, head.is_ascii_uppercase()))
+    }),
+    Box::new(|state| {
+      let (state, name) = parser::name1(state)?;
+      let (state, args) = parse_arguments(state)?;
+      let (state, _) = parser::consume(":", state)?;
+      let (state, tipo) = parse_term(state)?;
+      let mut rules = Vec::new();
+      let mut state = state;
+      loop {
+        let (new_state, rule) = parse_rule(state)?;
+        match rule {
+          Some(rule) if rule_head_name(&rule) == Some(name.as_str()) => {
+            rules.push(rule);
+            state = new_state;
+          }
+          _ => break,
+        }
+      }
+      Ok((state, Entry { name, args, tipo, rules }))
+    }),
+    state,
+  )
+}
+
 pub fn parse_file(state: parser::State) -> parser::Answer<File> {
-  let mut rules = Vec::new();
+  let mut entries = Vec::new();
   let mut state = state;
   loop {
     let (new_state, done) = parser::done(state)?;
     if done {
       break;
     }
-    let (new_state, rule) = parse_rule(new_state)?;
-    if let Some(rule) = rule {
-      rules.push(rule);
+    let (new_state, entry) = parse_entry(new_state)?;
+    if let Some(entry) = entry {
+      entries.push(entry);
     } else {
       return parser::expected("definition", 1, state);
     }
     state = new_state;
   }
 
-  Ok((state, File { rules }))
+  Ok((state, File { entries }))
 }
 
 pub fn read_term(code: &str) -> Result<Box<Term>, String> {
@@ -525,3 +1009,420 @@ pub fn read_file(code: &str) -> Result<File, String> {
 pub fn read_rule(code: &str) -> Result<Option<Rule>, String> {
   parser::read(Box::new(parse_rule), code)
 }
+
+// Adjust
+// ======
+
+// The parser classifies purely by first character, so an applied uppercase symbol always
+// comes out as `Ctr`, whether it actually names a constructor or a defined function. This
+// pass resolves that ambiguity: it walks a term carrying the names currently bound by
+// lambdas, `let`/`dup`, and (for a rule's right-hand side, via `adjust_rule`) the rule's
+// own pattern variables, and turns an applied head into `Fun` when it names a file entry
+// and isn't shadowed by one of those bound names. Anything else (an unresolved symbol, or
+// one that is shadowed) is left as `Ctr`/`Var`/`App`, exactly as the parser produced it.
+
+impl File {
+  fn is_entry(&self, name: &str) -> bool {
+    self.entries.iter().any(|entry| entry.name == name)
+  }
+}
+
+fn adjust_with(file: &File, term: &Term, bound: &HashSet<String>) -> Term {
+  match term {
+    Term::Var { name } => Term::Var { name: name.clone() },
+    Term::Num { numb } => Term::Num { numb: *numb },
+    Term::Dup { nam0, nam1, expr, body } => {
+      let expr = Box::new(adjust_with(file, expr, bound));
+      let mut bound = bound.clone();
+      bound.insert(nam0.clone());
+      bound.insert(nam1.clone());
+      let body = Box::new(adjust_with(file, body, &bound));
+      Term::Dup { nam0: nam0.clone(), nam1: nam1.clone(), expr, body }
+    }
+    Term::Let { name, expr, body } => {
+      let expr = Box::new(adjust_with(file, expr, bound));
+      let mut bound = bound.clone();
+      bound.insert(name.clone());
+      let body = Box::new(adjust_with(file, body, &bound));
+      Term::Let { name: name.clone(), expr, body }
+    }
+    Term::Lam { name, body } => {
+      let mut bound = bound.clone();
+      bound.insert(name.clone());
+      let body = Box::new(adjust_with(file, body, &bound));
+      Term::Lam { name: name.clone(), body }
+    }
+    Term::Op2 { oper, val0, val1 } => {
+      let val0 = Box::new(adjust_with(file, val0, bound));
+      let val1 = Box::new(adjust_with(file, val1, bound));
+      Term::Op2 { oper: *oper, val0, val1 }
+    }
+    Term::App { func, argm } => {
+      // flatten the App-chain so a partially or fully applied function (or constructor)
+      // is classified by its head, not rebuilt one argument at a time
+      let mut args = vec![Box::new(adjust_with(file, argm, bound))];
+      let mut head = func.as_ref();
+      while let Term::App { func, argm } = head {
+        args.push(Box::new(adjust_with(file, argm, bound)));
+        head = func;
+      }
+      args.reverse();
+      match head {
+        Term::Var { name } if !bound.contains(name) && file.is_entry(name) => {
+          Term::Fun { name: name.clone(), args }
+        }
+        _ => {
+          let head = adjust_with(file, head, bound);
+          args.into_iter().fold(head, |func, argm| Term::App { func: Box::new(func), argm })
+        }
+      }
+    }
+    Term::Ctr { name, args } => {
+      let args = args.iter().map(|arg| Box::new(adjust_with(file, arg, bound))).collect();
+      if !bound.contains(name) && file.is_entry(name) {
+        Term::Fun { name: name.clone(), args }
+      } else {
+        Term::Ctr { name: name.clone(), args }
+      }
+    }
+    Term::Fun { name, args } => {
+      let args = args.iter().map(|arg| Box::new(adjust_with(file, arg, bound))).collect();
+      Term::Fun { name: name.clone(), args }
+    }
+    Term::Match { expr, arms } => {
+      let expr = Box::new(adjust_with(file, expr, bound));
+      let arms = arms
+        .iter()
+        .map(|(patt, body)| {
+          let mut bound = bound.clone();
+          lhs_vars(patt, &mut bound);
+          (patt.clone(), Box::new(adjust_with(file, body, &bound)))
+        })
+        .collect();
+      Term::Match { expr, arms }
+    }
+  }
+}
+
+// Resolves `Var`/`Ctr`/`Fun` roles in `term`, with no names bound yet. Exposed for callers
+// that already have a term detached from any rule (e.g. a REPL expression); rule bodies
+// should go through `adjust_rule` instead, so their pattern variables shadow entries.
+pub fn adjust(file: &File, term: &Term) -> Term {
+  adjust_with(file, term, &HashSet::new())
+}
+
+// Collects every name a constructor pattern binds, so a rule's right-hand side can treat
+// them as already-bound (shadowing any same-named entry).
+fn lhs_vars(term: &Term, vars: &mut HashSet<String>) {
+  match term {
+    Term::Var { name } => {
+      vars.insert(name.clone());
+    }
+    Term::Ctr { args, .. } | Term::Fun { args, .. } => {
+      for arg in args {
+        lhs_vars(arg, vars);
+      }
+    }
+    _ => {}
+  }
+}
+
+pub fn adjust_rule(file: &File, rule: &Rule) -> Rule {
+  let mut bound = HashSet::new();
+  lhs_vars(&rule.lhs, &mut bound);
+  let rhs = Box::new(adjust_with(file, &rule.rhs, &bound));
+  Rule { lhs: rule.lhs.clone(), rhs }
+}
+
+pub fn adjust_file(file: &File) -> File {
+  let entries = file
+    .entries
+    .iter()
+    .map(|entry| Entry {
+      name: entry.name.clone(),
+      args: entry.args.clone(),
+      tipo: entry.tipo.clone(),
+      rules: entry.rules.iter().map(|rule| adjust_rule(file, rule)).collect(),
+    })
+    .collect();
+  File { entries }
+}
+
+// Match
+// =====
+
+// Replaces every name in `vars` throughout `term`, including binder names, so a hoisted
+// match arm can never collide with another arm or a name already in scope at its call site.
+fn rename(term: &Term, vars: &HashMap<String, String>) -> Term {
+  fn sub(name: &str, vars: &HashMap<String, String>) -> String {
+    vars.get(name).cloned().unwrap_or_else(|| name.to_string())
+  }
+  match term {
+    Term::Var { name } => Term::Var { name: sub(name, vars) },
+    Term::Num { numb } => Term::Num { numb: *numb },
+    Term::Dup { nam0, nam1, expr, body } => Term::Dup {
+      nam0: sub(nam0, vars),
+      nam1: sub(nam1, vars),
+      expr: Box::new(rename(expr, vars)),
+      body: Box::new(rename(body, vars)),
+    },
+    Term::Let { name, expr, body } => Term::Let {
+      name: sub(name, vars),
+      expr: Box::new(rename(expr, vars)),
+      body: Box::new(rename(body, vars)),
+    },
+    Term::Lam { name, body } => {
+      Term::Lam { name: sub(name, vars), body: Box::new(rename(body, vars)) }
+    }
+    Term::App { func, argm } => {
+      Term::App { func: Box::new(rename(func, vars)), argm: Box::new(rename(argm, vars)) }
+    }
+    Term::Ctr { name, args } => {
+      Term::Ctr { name: name.clone(), args: args.iter().map(|a| Box::new(rename(a, vars))).collect() }
+    }
+    Term::Fun { name, args } => {
+      Term::Fun { name: name.clone(), args: args.iter().map(|a| Box::new(rename(a, vars))).collect() }
+    }
+    Term::Op2 { oper, val0, val1 } => {
+      Term::Op2 { oper: *oper, val0: Box::new(rename(val0, vars)), val1: Box::new(rename(val1, vars)) }
+    }
+    Term::Match { expr, arms } => Term::Match {
+      expr: Box::new(rename(expr, vars)),
+      arms: arms.iter().map(|(p, b)| (Box::new(rename(p, vars)), Box::new(rename(b, vars)))).collect(),
+    },
+  }
+}
+
+// A bare variable pattern (including `_`) matches anything, so its rule is the catch-all.
+fn is_wildcard_pattern(patt: &Term) -> bool {
+  matches!(patt, Term::Var { .. })
+}
+
+struct MatchCtx {
+  fresh: usize,
+  extra: Vec<Entry>,
+}
+
+impl MatchCtx {
+  fn gensym(&mut self, hint: &str) -> String {
+    self.fresh += 1;
+    format!("{}$M{}", hint, self.fresh)
+  }
+}
+
+// Walks `term` replacing every `Match` with a call to a freshly-named auxiliary function. A
+// match takes its name before its arm bodies are lowered, but records its entry after theirs,
+// so a nested match's auxiliary entry comes before the enclosing match's. The synthesized
+// entry's equations are exactly the arms, applied to the scrutinee, so they reuse the existing
+// rule machinery; a catch-all arm (a bare variable pattern, `_` included) is moved last,
+// after every other arm, so it only catches what nothing else matched.
+fn desugar_term(ctx: &mut MatchCtx, term: &Term) -> Term {
+  match term {
+    Term::Var { name } => Term::Var { name: name.clone() },
+    Term::Num { numb } => Term::Num { numb: *numb },
+    Term::Dup { nam0, nam1, expr, body } => Term::Dup {
+      nam0: nam0.clone(),
+      nam1: nam1.clone(),
+      expr: Box::new(desugar_term(ctx, expr)),
+      body: Box::new(desugar_term(ctx, body)),
+    },
+    Term::Let { name, expr, body } => Term::Let {
+      name: name.clone(),
+      expr: Box::new(desugar_term(ctx, expr)),
+      body: Box::new(desugar_term(ctx, body)),
+    },
+    Term::Lam { name, body } => Term::Lam { name: name.clone(), body: Box::new(desugar_term(ctx, body)) },
+    Term::App { func, argm } => {
+      Term::App { func: Box::new(desugar_term(ctx, func)), argm: Box::new(desugar_term(ctx, argm)) }
+    }
+    Term::Ctr { name, args } => Term::Ctr {
+      name: name.clone(),
+      args: args.iter().map(|a| Box::new(desugar_term(ctx, a))).collect(),
+    },
+    Term::Fun { name, args } => Term::Fun {
+      name: name.clone(),
+      args: args.iter().map(|a| Box::new(desugar_term(ctx, a))).collect(),
+    },
+    Term::Op2 { oper, val0, val1 } => Term::Op2 {
+      oper: *oper,
+      val0: Box::new(desugar_term(ctx, val0)),
+      val1: Box::new(desugar_term(ctx, val1)),
+    },
+    Term::Match { expr, arms } => {
+      let expr = Box::new(desugar_term(ctx, expr));
+      let name = ctx.gensym("Match");
+      let mut rules = Vec::new();
+      let mut default_rules = Vec::new();
+      for (patt, body) in arms {
+        let mut fresh_vars = HashSet::new();
+        lhs_vars(patt, &mut fresh_vars);
+        let renaming: HashMap<String, String> =
+          fresh_vars.into_iter().map(|var| (var.clone(), ctx.gensym(&var))).collect();
+        let patt = rename(patt, &renaming);
+        let body = desugar_term(ctx, &rename(body, &renaming));
+        let rule =
+          Rule { lhs: Box::new(Term::Ctr { name: name.clone(), args: vec![Box::new(patt.clone())] }), rhs: Box::new(body) };
+        if is_wildcard_pattern(&patt) {
+          default_rules.push(rule);
+        } else {
+          rules.push(rule);
+        }
+      }
+      rules.extend(default_rules);
+      let args = vec![Argument { eras: false, name: "x".to_string(), tipo: Box::new(Term::Var { name: "_".to_string() }) }];
+      let tipo = Box::new(Term::Var { name: "_".to_string() });
+      ctx.extra.push(Entry { name: name.clone(), args, tipo, rules });
+      Term::Fun { name, args: vec![expr] }
+    }
+  }
+}
+
+// Lowers every `match` in a file into a fresh top-level entry per occurrence, appended after
+// the entries that were already there.
+pub fn desugar_matches(file: &File) -> File {
+  let mut ctx = MatchCtx { fresh: 0, extra: Vec::new() };
+  let mut entries: Vec<Entry> = file
+    .entries
+    .iter()
+    .map(|entry| Entry {
+      name: entry.name.clone(),
+      args: entry.args.clone(),
+      tipo: entry.tipo.clone(),
+      rules: entry
+        .rules
+        .iter()
+        .map(|rule| Rule { lhs: rule.lhs.clone(), rhs: Box::new(desugar_term(&mut ctx, &rule.rhs)) })
+        .collect(),
+    })
+    .collect();
+  entries.extend(ctx.extra);
+  File { entries }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_a_single_rule_entry() {
+    let file = read_file("Id (a: Nat) : Nat\n(Id a) = a").unwrap();
+    assert_eq!(file.entries.len(), 1);
+    assert_eq!(file.entries[0].name, "Id");
+    assert_eq!(file.entries[0].rules.len(), 1);
+  }
+
+  #[test]
+  fn bare_rule_rhs_does_not_swallow_the_next_equation() {
+    let code = "Const (a: Nat) (b: Nat) : Nat\n(Const a b) = a\n(Const a b) = a";
+    let file = read_file(code).unwrap();
+    assert_eq!(file.entries.len(), 1);
+    assert_eq!(file.entries[0].rules.len(), 2);
+  }
+
+  #[test]
+  fn bare_rule_rhs_does_not_swallow_the_next_entry() {
+    let code = "Add (a: Nat) (b: Nat) : Nat\n(Add a Z) = a\n(Add a (S b)) = (S (Add a b))\n\nZero : Nat\n(Zero) = (Z)";
+    let file = read_file(code).unwrap();
+    assert_eq!(file.entries.len(), 2);
+    assert_eq!(file.entries[0].name, "Add");
+    assert_eq!(file.entries[0].rules.len(), 2);
+    assert_eq!(file.entries[1].name, "Zero");
+    assert_eq!(file.entries[1].rules.len(), 1);
+  }
+
+  #[test]
+  fn parses_a_bare_application_rule_rhs() {
+    let file = read_file("Apply (f: Nat) (x: Nat) : Nat\n(Apply f x) = f x").unwrap();
+    assert_eq!(file.entries[0].rules.len(), 1);
+    match &*file.entries[0].rules[0].rhs {
+      Term::App { func, argm } => {
+        assert!(matches!(&**func, Term::Var { name } if name == "f"));
+        assert!(matches!(&**argm, Term::Var { name } if name == "x"));
+      }
+      other => panic!("expected a bare App, got {:?}", other),
+    }
+  }
+
+  #[test]
+  fn adjust_shadowing_keeps_a_lambda_bound_name_as_ctr() {
+    // `λZero Zero` rebinds the `Zero` entry's own name as a lambda parameter, so the
+    // reference to `Zero` in its body must stay a `Ctr`, not resolve to the entry's `Fun`.
+    let code = "Zero : Nat\n(Zero) = Z\nUseZero : Nat\n(UseZero) = λZero Zero";
+    let file = read_file(code).unwrap();
+    let file = adjust_file(&file);
+    match &*file.entries[1].rules[0].rhs {
+      Term::Lam { body, .. } => match &**body {
+        Term::Ctr { name, .. } => assert_eq!(name, "Zero"),
+        other => panic!("expected a shadowed Ctr, got {:?}", other),
+      },
+      other => panic!("expected a Lam, got {:?}", other),
+    }
+  }
+
+  #[test]
+  fn adjust_resolves_a_nullary_constructor() {
+    let file = read_file("Zero : Nat\n(Zero) = Z").unwrap();
+    let file = adjust_file(&file);
+    match &*file.entries[0].rules[0].rhs {
+      Term::Ctr { name, args } => {
+        assert_eq!(name, "Z");
+        assert!(args.is_empty());
+      }
+      other => panic!("expected a nullary Ctr, got {:?}", other),
+    }
+  }
+
+  #[test]
+  fn adjust_resolves_a_partially_applied_function() {
+    let file = read_file("Add (a: Nat) (b: Nat) : Nat\n(Add a b) = a\nUseAdd : Nat\n(UseAdd) = (Add Z)").unwrap();
+    let file = adjust_file(&file);
+    match &*file.entries[1].rules[0].rhs {
+      Term::Fun { name, args } => {
+        assert_eq!(name, "Add");
+        assert_eq!(args.len(), 1);
+      }
+      other => panic!("expected a partially applied Fun, got {:?}", other),
+    }
+  }
+
+  #[test]
+  fn desugar_matches_moves_the_wildcard_arm_last() {
+    fn arm_pattern(rule: &Rule) -> &Term {
+      match &*rule.lhs {
+        Term::Ctr { args, .. } => &args[0],
+        other => panic!("expected a Ctr pattern wrapper, got {:?}", other),
+      }
+    }
+    let file = read_file("Test (x: Nat) : Nat\n(Test x) = match x { Z: Z; n: n }").unwrap();
+    let file = desugar_matches(&file);
+    let aux = &file.entries[1];
+    assert_eq!(aux.rules.len(), 2);
+    assert!(!is_wildcard_pattern(arm_pattern(&aux.rules[0])));
+    assert!(is_wildcard_pattern(arm_pattern(&aux.rules[1])));
+  }
+
+  #[test]
+  fn desugar_matches_renames_arm_variables_apart_across_matches() {
+    let code = "Test (x: Nat) (y: Nat) : Nat\n(Test x y) = match x { n: match y { n: n } }";
+    let file = read_file(code).unwrap();
+    let file = desugar_matches(&file);
+    // two auxiliary entries, one per `match`, each with its own fresh `n`
+    assert_eq!(file.entries.len(), 3);
+    // the inner match's entry is recorded before the outer one's
+    let outer_pattern_name = match &*file.entries[2].rules[0].lhs {
+      Term::Ctr { args, .. } => match &*args[0] {
+        Term::Var { name } => name.clone(),
+        other => panic!("expected a Var pattern, got {:?}", other),
+      },
+      other => panic!("expected a Ctr pattern, got {:?}", other),
+    };
+    let inner_pattern_name = match &*file.entries[1].rules[0].lhs {
+      Term::Ctr { args, .. } => match &*args[0] {
+        Term::Var { name } => name.clone(),
+        other => panic!("expected a Var pattern, got {:?}", other),
+      },
+      other => panic!("expected a Ctr pattern, got {:?}", other),
+    };
+    assert_ne!(outer_pattern_name, inner_pattern_name);
+  }
+}